@@ -0,0 +1,147 @@
+//! gitignore-style pattern matching for the corpus walk.
+//!
+//! Patterns are partitioned by shape so that the common cases are cheap:
+//! exact filenames go in a `HashSet` for O(1) lookup, fixed extensions
+//! (`*.wav`) go in a small extension set, and only patterns with real
+//! glob metacharacters (`*`, `?`, `[`, `**`) are compiled to a regex.
+//! Matching is always against the entry's own file name, not a full
+//! relative path, which is enough for the flat per-directory patterns
+//! TalkBank corpora actually use.
+
+use regex::Regex;
+use std::collections::HashSet;
+use std::path::Path;
+
+/// The accumulated ignore patterns in effect for one directory: its
+/// ancestors' patterns merged with its own `.chatignore`/`.gitignore`.
+#[derive(Debug, Clone, Default)]
+pub struct IgnoreSet {
+    literals: HashSet<String>,
+    extensions: HashSet<String>,
+    globs: Vec<String>,
+    compiled: Vec<Regex>,
+}
+
+impl IgnoreSet {
+    pub fn is_empty(&self) -> bool {
+        self.literals.is_empty() && self.extensions.is_empty() && self.globs.is_empty()
+    }
+
+    /// Whether `file_name` (a bare file or directory name, no path
+    /// separators) should be pruned from the walk.
+    pub fn matches(&self, file_name: &str) -> bool {
+        if self.literals.contains(file_name) {
+            return true;
+        }
+        if let Some(ext) = Path::new(file_name).extension().and_then(|e| e.to_str()) {
+            if self.extensions.contains(ext) {
+                return true;
+            }
+        }
+        self.compiled.iter().any(|re| re.is_match(file_name))
+    }
+
+    /// Combine an inherited set with patterns found further down the
+    /// tree; the child's own patterns are added on top.
+    pub fn merged_with(&self, child: IgnoreSet) -> IgnoreSet {
+        let mut merged = self.clone();
+        merged.literals.extend(child.literals);
+        merged.extensions.extend(child.extensions);
+        merged.globs.extend(child.globs);
+        merged.compiled.extend(child.compiled);
+        merged
+    }
+}
+
+const GLOB_METACHARS: [char; 3] = ['*', '?', '['];
+
+/// Parse one ignore file's worth of lines (gitignore syntax, minus `!`
+/// negation and leading-`/` anchoring, which TalkBank corpora don't use).
+/// A trailing `/` is the standard directory marker (`media/`), not
+/// anchoring, so it's stripped before a pattern is classified; it
+/// matches the same entry names with or without it.
+pub fn parse_ignore_lines<'a>(lines: impl Iterator<Item = &'a str>) -> IgnoreSet {
+    let mut set = IgnoreSet::default();
+    for raw_line in lines {
+        let pattern = raw_line.trim();
+        if pattern.is_empty() || pattern.starts_with('#') {
+            continue;
+        }
+        let pattern = pattern.strip_suffix('/').unwrap_or(pattern);
+        if pattern.is_empty() {
+            continue;
+        }
+
+        if let Some(ext) = pattern.strip_prefix("*.") {
+            // Multi-dot suffixes (`*.tar.gz`) can't be looked up by
+            // `Path::extension`, so they go through the glob path below.
+            if !ext.is_empty() && !ext.contains(GLOB_METACHARS) && !ext.contains('.') {
+                set.extensions.insert(ext.to_owned());
+                continue;
+            }
+        }
+
+        if pattern.contains(GLOB_METACHARS) {
+            if let Ok(re) = Regex::new(&glob_to_regex(pattern)) {
+                set.globs.push(pattern.to_owned());
+                set.compiled.push(re);
+            }
+            continue;
+        }
+
+        set.literals.insert(pattern.to_owned());
+    }
+    set
+}
+
+/// Translate a single gitignore-style glob into an anchored regex.
+/// `**` matches across path separators, a lone `*` does not, `?` matches
+/// one character, `[...]` is passed through as a regex character class,
+/// and other regex metacharacters are escaped.
+fn glob_to_regex(pattern: &str) -> String {
+    let mut regex = String::from("(?m)^");
+    let mut chars = pattern.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                regex.push_str(".*");
+            }
+            '*' => regex.push_str("[^/]*"),
+            '?' => regex.push_str("[^/]"),
+            c if is_regex_metachar(c) => {
+                regex.push('\\');
+                regex.push(c);
+            }
+            c => regex.push(c),
+        }
+    }
+    regex.push('$');
+    regex
+}
+
+/// Characters with special meaning in a regex that must be escaped when
+/// they appear literally in a translated glob.
+fn is_regex_metachar(c: char) -> bool {
+    matches!(
+        c,
+        '.' | '+' | '(' | ')' | '|' | '^' | '$' | '{' | '}' | '\\'
+    )
+}
+
+/// Read and parse a single ignore file; a missing file is just an empty
+/// set, matching how `0types.txt` absence is handled.
+pub fn load_ignore_file(path: &Path) -> IgnoreSet {
+    match std::fs::read_to_string(path) {
+        Ok(contents) => parse_ignore_lines(contents.lines()),
+        Err(_) => IgnoreSet::default(),
+    }
+}
+
+/// Load the combined `.chatignore` + `.gitignore` patterns defined
+/// directly in `dir` (not including anything inherited from ancestors).
+pub fn load_dir_ignores(dir: &Path) -> IgnoreSet {
+    let chatignore = load_ignore_file(&dir.join(".chatignore"));
+    let gitignore = load_ignore_file(&dir.join(".gitignore"));
+    chatignore.merged_with(gitignore)
+}
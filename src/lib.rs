@@ -1,49 +1,104 @@
+use crate::ignore::IgnoreSet;
 use lazy_static::lazy_static;
+use rayon::prelude::*;
 use regex::Captures;
 use regex::Regex;
+use std::cell::RefCell;
 use std::collections::HashMap;
 use std::collections::HashSet;
-use std::ffi::OsStr;
+use std::ffi::OsString;
 use std::fs::read_to_string;
 use std::fs::File;
 use std::io::prelude::*;
 use std::io::BufReader;
+use std::path::Path;
 use std::path::PathBuf;
 use tempfile::NamedTempFile;
 use walkdir::WalkDir;
 
+mod ignore;
+
 static DEBUG: bool = false;
 
 lazy_static! {
     static ref TYPES_REGEX: Regex = Regex::new(r"(?m)^@Types:.+$").unwrap();
 }
 
+/// Directories that have their own 0types.txt, the nearest-ancestor
+/// 0types.txt directory inherited by every directory in the tree, and
+/// the ignore patterns in effect in every directory.
+pub type CorpusWalkInfo = (
+    HashSet<PathBuf>,
+    HashMap<PathBuf, Option<PathBuf>>,
+    HashMap<PathBuf, IgnoreSet>,
+);
+
 /// Find all 0types.txt and return a set of directories that have them,
-/// along with a map of each subdirectory to itself or None.
-pub fn collect_chat_types(path: &str) -> (HashSet<PathBuf>, HashMap<PathBuf, Option<PathBuf>>) {
+/// along with a map of each subdirectory to itself or None, and a map of
+/// each directory to the `.chatignore`/`.gitignore` patterns in effect
+/// there (its own plus everything inherited from ancestors, plus
+/// `extra_ignore_globs`, which apply everywhere in the tree). A
+/// directory matched by its parent's ignore patterns prunes the whole
+/// subtree, the same way `types_map` inheritance works.
+pub fn collect_chat_types(path: &str, extra_ignore_globs: &[String]) -> CorpusWalkInfo {
     let mut types_dirs: HashSet<PathBuf> = HashSet::new();
 
     // Map to which closest ancestor directory has 0types.txt, if any at all.
     let mut types_map: HashMap<PathBuf, Option<PathBuf>> = HashMap::new();
 
-    // Don't go into .git directories.
-    // Rely on depth-first.
-    for result_entry in WalkDir::new(path)
-        .into_iter()
-        .filter_entry(|e| e.file_name().to_str().map(|s| s != ".git").unwrap_or(false))
-    {
+    // The CLI-supplied patterns apply everywhere, so they seed the root
+    // of the inheritance chain rather than being checked separately.
+    let global_ignores = ignore::parse_ignore_lines(extra_ignore_globs.iter().map(|s| s.as_str()));
+
+    // Map to the combined ignore patterns in effect in each directory.
+    // RefCell'd because the filter_entry closure below needs to consult
+    // it (to decide whether to prune) while the loop body below is still
+    // the only thing that mutates it, depth-first.
+    let ignore_map: RefCell<HashMap<PathBuf, IgnoreSet>> = RefCell::new(HashMap::new());
+
+    // Don't go into .git directories, or ones ignored by an ancestor's
+    // .chatignore/.gitignore (or by extra_ignore_globs). Rely on
+    // depth-first.
+    for result_entry in WalkDir::new(path).into_iter().filter_entry(|e| {
+        let name = match e.file_name().to_str() {
+            Some(name) => name,
+            None => return false,
+        };
+        if name == ".git" {
+            return false;
+        }
+        match e.path().parent() {
+            Some(parent) => !ignore_map
+                .borrow()
+                .get(parent)
+                .map(|set| set.matches(name))
+                .unwrap_or(false),
+            None => true,
+        }
+    }) {
         let entry = result_entry.unwrap();
         let file_type = entry.file_type();
         if file_type.is_dir() {
             let dir_path = entry.into_path();
             let dir_path_str = dir_path.to_str().unwrap();
-            if dir_path_str == path {
-                types_map.insert(dir_path, None);
+            let inherited = if dir_path_str == path {
+                types_map.insert(dir_path.clone(), None);
+                global_ignores.clone()
             } else {
                 // Inherit from the parent's (already filled in depth-first).
                 let parent_dir_path = dir_path.parent().unwrap().to_path_buf();
-                types_map.insert(dir_path, types_map.get(&parent_dir_path).unwrap().clone());
-            }
+                types_map.insert(
+                    dir_path.clone(),
+                    types_map.get(&parent_dir_path).unwrap().clone(),
+                );
+                ignore_map
+                    .borrow()
+                    .get(&parent_dir_path)
+                    .cloned()
+                    .unwrap_or_default()
+            };
+            let combined = inherited.merged_with(ignore::load_dir_ignores(&dir_path));
+            ignore_map.borrow_mut().insert(dir_path, combined);
         } else if file_type.is_file() {
             let file_name_str = entry.file_name().to_str().unwrap();
             if file_name_str == "0types.txt" {
@@ -55,7 +110,7 @@ pub fn collect_chat_types(path: &str) -> (HashSet<PathBuf>, HashMap<PathBuf, Opt
             // Skip symlink.
         }
     }
-    (types_dirs, types_map)
+    (types_dirs, types_map, ignore_map.into_inner())
 }
 
 /// Extract @Types header if any, by slurping in whole file.
@@ -214,12 +269,73 @@ pub fn update_types_to_output<W: Write>(
     return updated;
 }
 
+/// On Linux, atomically swap the files at `from` and `to` via
+/// `renameat2(2)` with `RENAME_EXCHANGE`, so that a crash mid-rename can
+/// never leave `to` truncated or half-written: it is always either the
+/// old file or the new one. After a successful call, `from` holds
+/// whatever used to live at `to`.
+#[cfg(target_os = "linux")]
+fn renameat2_exchange(from: &Path, to: &Path) -> std::io::Result<()> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let from_c = CString::new(from.as_os_str().as_bytes()).unwrap();
+    let to_c = CString::new(to.as_os_str().as_bytes()).unwrap();
+    let ret = unsafe {
+        libc::renameat2(
+            libc::AT_FDCWD,
+            from_c.as_ptr(),
+            libc::AT_FDCWD,
+            to_c.as_ptr(),
+            libc::RENAME_EXCHANGE,
+        )
+    };
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(std::io::Error::last_os_error())
+    }
+}
+
+/// Atomically move `temp_path` into place at `new_path`.
+///
+/// When `preserve_metadata` is set and we're on Linux, this exchanges the
+/// two files in place with `renameat2(RENAME_EXCHANGE)` and then unlinks
+/// the displaced original, so the rename step itself can never be
+/// observed as a truncated file. Everywhere else it falls back to a plain
+/// same-filesystem rename via `persist`, which is still atomic, just not
+/// crash-swap-safe.
+#[cfg(target_os = "linux")]
+fn finish_atomic_move(temp_path: tempfile::TempPath, new_path: &str, preserve_metadata: bool) {
+    if preserve_metadata
+        && Path::new(new_path).exists()
+        && renameat2_exchange(&temp_path, Path::new(new_path)).is_ok()
+    {
+        // temp_path now holds the displaced original; remove it.
+        let _ = temp_path.close();
+        return;
+    }
+    temp_path.persist(new_path).unwrap();
+}
+
+#[cfg(not(target_os = "linux"))]
+fn finish_atomic_move(temp_path: tempfile::TempPath, new_path: &str, _preserve_metadata: bool) {
+    temp_path.persist(new_path).unwrap();
+}
+
 /// Write to a temporary file before moving to new_path.
+///
+/// When `preserve_metadata` is set, the temp file is created alongside
+/// `new_path` (so the final move is a same-filesystem rename, never a
+/// cross-device copy) and is given the source file's permission bits
+/// and mtime before being swapped into place, so an existing transcript's
+/// mode and timestamp survive the update.
 pub fn update_types_to_new_path(
     path: &str,
     new_path: &str,
     new_types: &str,
     dry_run: bool,
+    preserve_metadata: bool,
 ) -> bool {
     let file = File::open(path).unwrap();
     let buf_read = BufReader::new(file);
@@ -237,16 +353,48 @@ pub fn update_types_to_new_path(
         let updated = updated_prefix(&mut strings, new_types, Some(&mut prefix));
 
         if updated {
-            // Use temporary file to write everything out to.
-            let mut named_temp_file = NamedTempFile::new().unwrap();
+            let source_metadata = if preserve_metadata {
+                Some(std::fs::metadata(path).unwrap())
+            } else {
+                None
+            };
+
+            // Use a temporary file to write everything out to. With
+            // preserve_metadata, put it next to new_path so the final
+            // rename stays on the same filesystem instead of risking a
+            // cross-device copy; otherwise it goes in the system temp dir.
+            let new_path_dir = Path::new(new_path)
+                .parent()
+                .filter(|dir| !dir.as_os_str().is_empty())
+                .unwrap_or_else(|| Path::new("."));
+            let mut named_temp_file = if preserve_metadata {
+                tempfile::Builder::new().tempfile_in(new_path_dir).unwrap()
+            } else {
+                NamedTempFile::new().unwrap()
+            };
             named_temp_file.write_all(&prefix).unwrap();
             while let Some(line) = strings.next() {
                 writeln!(named_temp_file, "{}", line).unwrap();
             }
+            named_temp_file.flush().unwrap();
+
+            if let Some(metadata) = source_metadata {
+                named_temp_file
+                    .as_file()
+                    .set_permissions(metadata.permissions())
+                    .unwrap();
+                if let Ok(modified) = metadata.modified() {
+                    let _ = named_temp_file.as_file().set_modified(modified);
+                }
+            }
 
-            // Finally, persist to new_path, which could have been
-            // the same as path.
-            named_temp_file.persist(new_path).unwrap();
+            if preserve_metadata {
+                finish_atomic_move(named_temp_file.into_temp_path(), new_path, true);
+            } else {
+                // Finally, persist to new_path, which could have been
+                // the same as path.
+                named_temp_file.persist(new_path).unwrap();
+            }
         }
         updated
     }
@@ -268,17 +416,39 @@ pub fn read_types_file(path: &str) -> String {
     panic!("Expected @Types: header, got nothing");
 }
 
-/// Collect all 0types.txt under base_path, the apply modifications
-/// to all CHAT files as appropriate. Return number of files actually
+/// Collect all 0types.txt under base_path, then apply modifications to
+/// all CHAT files as appropriate. Return number of files actually
 /// changed.
-pub fn update_types_in_place(base_path: &str, dry_run: bool) -> u32 {
-    lazy_static! {
-        static ref CHAT_FILE_EXTENSION: &'static OsStr = OsStr::new("cha");
-    }
-
-    let mut num_updated = 0;
+///
+/// When `preserve_metadata` is set, each rewritten file keeps its
+/// original permission bits and mtime and is moved into place from a
+/// temp file in the same directory, on Linux via a crash-safe atomic
+/// swap and elsewhere via a plain same-filesystem rename; see
+/// `update_types_to_new_path`.
+///
+/// `extra_extensions` are matched in addition to the default `cha`
+/// extension, and `extra_ignore_globs` are gitignore-style patterns
+/// applied everywhere in the tree, on top of any `.chatignore`/
+/// `.gitignore` files discovered during the walk.
+///
+/// The `0types.txt` discovery walk stays single-threaded and
+/// depth-first, since later directories depend on earlier ones via
+/// `types_map`/`ignore_map` inheritance. Once that's done, though, each
+/// candidate CHAT file's rewrite is independent, so candidates are
+/// gathered into a `Vec` and then fanned out across `jobs` threads.
+pub fn update_types_in_place(
+    base_path: &str,
+    dry_run: bool,
+    preserve_metadata: bool,
+    extra_extensions: &[String],
+    extra_ignore_globs: &[String],
+    jobs: usize,
+) -> u32 {
+    let (types_dirs, types_map, ignore_map) = collect_chat_types(base_path, extra_ignore_globs);
 
-    let (types_dirs, types_map) = collect_chat_types(base_path);
+    let mut extensions: HashSet<OsString> = HashSet::new();
+    extensions.insert(OsString::from("cha"));
+    extensions.extend(extra_extensions.iter().map(OsString::from));
 
     // Parse all the @Types files.
     let types_info: HashMap<PathBuf, String> = types_dirs
@@ -290,26 +460,71 @@ pub fn update_types_in_place(base_path: &str, dry_run: bool) -> u32 {
         })
         .collect();
 
-    // For each CHAT file, update the @Types header if necessary.
-    for result_entry in WalkDir::new(base_path)
+    // Gather the candidate CHAT files under a types_dir. Reuses the
+    // ignore_map built by collect_chat_types (which already folds in
+    // extra_ignore_globs) so an ignored directory is pruned here too,
+    // the same way types_map is reused.
+    let candidate_paths: Vec<PathBuf> = WalkDir::new(base_path)
         .into_iter()
-        .filter_entry(|e| e.file_name().to_str().map(|s| s != ".git").unwrap_or(false))
-    {
-        let entry = result_entry.unwrap();
-        let path = entry.path();
-        let file_type = entry.file_type();
-        if file_type.is_file() && path.extension() == Some(&CHAT_FILE_EXTENSION) {
-            if let Some(types_dir) = types_map.get(path.parent().unwrap()).unwrap() {
+        .filter_entry(|e| {
+            let name = match e.file_name().to_str() {
+                Some(name) => name,
+                None => return false,
+            };
+            if name == ".git" {
+                return false;
+            }
+            match e.path().parent() {
+                Some(parent) => !ignore_map
+                    .get(parent)
+                    .map(|set| set.matches(name))
+                    .unwrap_or(false),
+                None => true,
+            }
+        })
+        .filter_map(|result_entry| {
+            let entry = result_entry.unwrap();
+            let is_file = entry.file_type().is_file();
+            let path = entry.into_path();
+            let matches_extension = path
+                .extension()
+                .map(|ext| extensions.contains(ext))
+                .unwrap_or(false);
+            let has_types = types_map
+                .get(path.parent().unwrap())
+                .map(|dir| dir.is_some())
+                .unwrap_or(false);
+            if is_file && matches_extension && has_types {
+                Some(path)
+            } else {
+                None
+            }
+        })
+        .collect();
+
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(jobs)
+        .build()
+        .unwrap();
+
+    // Each file's inherited types dir is looked up in the shared
+    // read-only types_map/types_info; update_types_to_new_path only
+    // touches its own path, so this is safe to run concurrently.
+    pool.install(|| {
+        candidate_paths
+            .par_iter()
+            .filter(|path| {
+                let types_dir = types_map
+                    .get(path.parent().unwrap())
+                    .unwrap()
+                    .as_ref()
+                    .unwrap();
                 let new_types = types_info.get(types_dir).unwrap();
                 let path_str = path.to_str().unwrap();
-                let updated = update_types_to_new_path(path_str, path_str, new_types, dry_run);
-                if updated {
-                    num_updated += 1;
-                }
-            }
-        }
-    }
-    num_updated
+                update_types_to_new_path(path_str, path_str, new_types, dry_run, preserve_metadata)
+            })
+            .count() as u32
+    })
 }
 
 #[cfg(test)]
@@ -358,40 +573,136 @@ mod test {
         }
     }
 
+    #[cfg(unix)]
+    #[test]
+    fn update_types_to_new_path_preserves_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("sample.cha");
+        std::fs::write(&path, "@Types:\tlong, toyplay, TD\n*CHI:\thi .\n").unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o640)).unwrap();
+
+        let path_str = path.to_str().unwrap();
+        let updated = update_types_to_new_path(
+            path_str,
+            path_str,
+            "@Types:\tlong, toyplay, FOO",
+            false,
+            true,
+        );
+        assert!(updated);
+
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode, 0o640);
+    }
+
     #[test]
     fn collect_chat_types_mixed() {
         let path = "test-dir";
-        let expected = (
-            [
+        let expected_types_dirs: HashSet<PathBuf> = [
+            PathBuf::from("test-dir/a"),
+            PathBuf::from("test-dir/b"),
+            PathBuf::from("test-dir/b/c"),
+        ]
+        .into_iter()
+        .collect();
+        let expected_types_map: HashMap<PathBuf, Option<PathBuf>> = [
+            (PathBuf::from("test-dir"), None),
+            (
                 PathBuf::from("test-dir/a"),
+                Some(PathBuf::from("test-dir/a")),
+            ),
+            (
                 PathBuf::from("test-dir/b"),
+                Some(PathBuf::from("test-dir/b")),
+            ),
+            (
                 PathBuf::from("test-dir/b/c"),
-            ]
-            .into_iter()
-            .collect(),
-            [
-                (PathBuf::from("test-dir"), None),
-                (
-                    PathBuf::from("test-dir/a"),
-                    Some(PathBuf::from("test-dir/a")),
-                ),
-                (
-                    PathBuf::from("test-dir/b"),
-                    Some(PathBuf::from("test-dir/b")),
-                ),
-                (
-                    PathBuf::from("test-dir/b/c"),
-                    Some(PathBuf::from("test-dir/b/c")),
-                ),
-                (
-                    PathBuf::from("test-dir/b/d"),
-                    Some(PathBuf::from("test-dir/b")),
-                ),
-            ]
-            .into_iter()
-            .collect(),
+                Some(PathBuf::from("test-dir/b/c")),
+            ),
+            (
+                PathBuf::from("test-dir/b/d"),
+                Some(PathBuf::from("test-dir/b")),
+            ),
+        ]
+        .into_iter()
+        .collect();
+
+        let (types_dirs, types_map, _ignore_map) = collect_chat_types(path, &[]);
+        assert_eq!(types_dirs, expected_types_dirs);
+        assert_eq!(types_map, expected_types_map);
+    }
+
+    #[test]
+    fn ignore_set_partitions_pattern_shapes() {
+        let set = ignore::parse_ignore_lines(
+            ["secret.txt", "*.wav", "private*", "# a comment", ""].into_iter(),
         );
+        assert!(set.matches("secret.txt"));
+        assert!(set.matches("audio.wav"));
+        assert!(set.matches("private-notes"));
+        assert!(!set.matches("public.cha"));
+    }
+
+    #[test]
+    fn ignore_set_strips_trailing_slash_directory_marker() {
+        let set = ignore::parse_ignore_lines(["media/", "private/"].into_iter());
+        assert!(set.matches("media"));
+        assert!(set.matches("private"));
+    }
+
+    #[test]
+    fn ignore_set_inheritance_prunes_subtree() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path();
+        std::fs::create_dir(base.join("keep")).unwrap();
+        std::fs::create_dir(base.join("skip")).unwrap();
+        std::fs::write(base.join(".chatignore"), "skip\n").unwrap();
+        std::fs::write(base.join("keep").join("a.cha"), "*CHI:\thi .\n").unwrap();
+        std::fs::write(base.join("skip").join("b.cha"), "*CHI:\thi .\n").unwrap();
+
+        let (_types_dirs, types_map, _ignore_map) = collect_chat_types(base.to_str().unwrap(), &[]);
+        assert!(types_map.contains_key(&base.join("keep")));
+        assert!(!types_map.contains_key(&base.join("skip")));
+    }
 
-        assert_eq!(collect_chat_types(path), expected);
+    #[test]
+    fn collect_chat_types_honors_extra_ignore_globs() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path();
+        std::fs::create_dir(base.join("keep")).unwrap();
+        std::fs::create_dir(base.join("skip")).unwrap();
+        std::fs::write(base.join("keep").join("a.cha"), "*CHI:\thi .\n").unwrap();
+        std::fs::write(base.join("skip").join("b.cha"), "*CHI:\thi .\n").unwrap();
+
+        let extra_ignore_globs = [String::from("skip")];
+        let (_types_dirs, types_map, _ignore_map) =
+            collect_chat_types(base.to_str().unwrap(), &extra_ignore_globs);
+        assert!(types_map.contains_key(&base.join("keep")));
+        assert!(!types_map.contains_key(&base.join("skip")));
+    }
+
+    #[test]
+    fn update_types_in_place_runs_in_parallel() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path();
+        std::fs::write(base.join("0types.txt"), "@Types:\tlong, toyplay, FOO\n").unwrap();
+        for name in ["a.cha", "b.cha", "c.cha"] {
+            std::fs::write(
+                base.join(name),
+                "@Types:\tlong, toyplay, OLD\n*CHI:\thi .\n",
+            )
+            .unwrap();
+        }
+
+        let num_updated =
+            update_types_in_place(base.to_str().unwrap(), false, false, &[], &[], 4);
+        assert_eq!(num_updated, 3);
+
+        for name in ["a.cha", "b.cha", "c.cha"] {
+            let contents = std::fs::read_to_string(base.join(name)).unwrap();
+            assert!(contents.starts_with("@Types:\tlong, toyplay, FOO\n"));
+        }
     }
 }
@@ -1,5 +1,7 @@
 use clap::Parser;
+use std::num::NonZeroUsize;
 use std::path::PathBuf;
+use std::thread::available_parallelism;
 use update_chat_types::update_types_in_place;
 
 /// Update CHAT files with correct @Types header.
@@ -13,12 +15,45 @@ struct Args {
     /// Whether to only output what would be done.
     #[clap(long)]
     dry_run: bool,
+
+    /// Preserve each file's permission bits and mtime across the update,
+    /// writing via a temp file in the same directory (on Linux, swapped
+    /// in with a crash-safe atomic exchange).
+    #[clap(long)]
+    preserve_permissions: bool,
+
+    /// Additional file extensions (beyond `cha`) to treat as CHAT files.
+    #[clap(long = "extra-extension")]
+    extra_extensions: Vec<String>,
+
+    /// Additional gitignore-style patterns to skip, on top of any
+    /// `.chatignore`/`.gitignore` files found while walking the corpus.
+    #[clap(long = "ignore-glob")]
+    ignore_globs: Vec<String>,
+
+    /// Number of threads to use for the per-file update pass. Defaults
+    /// to the number of available cores.
+    #[clap(long)]
+    jobs: Option<NonZeroUsize>,
 }
 
 fn main() {
     let args = Args::parse();
 
-    let num_updated = update_types_in_place(args.chat_dir.to_str().unwrap(), args.dry_run);
+    let jobs = args
+        .jobs
+        .or_else(|| available_parallelism().ok())
+        .map(NonZeroUsize::get)
+        .unwrap_or(1);
+
+    let num_updated = update_types_in_place(
+        args.chat_dir.to_str().unwrap(),
+        args.dry_run,
+        args.preserve_permissions,
+        &args.extra_extensions,
+        &args.ignore_globs,
+        jobs,
+    );
     println!(
         "{} {} CHAT files.",
         if args.dry_run {